@@ -1,67 +1,340 @@
 use anyhow::{anyhow, Context, Result};
 use clap::Parser;
+use futures::{future::OptionFuture, stream::StreamExt};
 use glob::glob;
-use log::trace;
+use inotify::{Inotify, WatchMask};
+use log::{info, trace, warn};
+use serde::Deserialize;
 use simplelog::TermLogger;
 use std::{
     fs::{File, OpenOptions},
     io::{Read, Write},
+    path::{Path, PathBuf},
     sync::Arc,
     time::{Duration, Instant},
 };
-use tokio::{sync::RwLock, time};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{Mutex, RwLock},
+    time,
+};
+
+const DEFAULT_DIM_TIMEOUT: u64 = 30;
+const DEFAULT_OFF_TIMEOUT: u64 = 60;
+const DEFAULT_OVERRIDE_GRACE: u64 = 10;
+const DEFAULT_AMBIENT_THRESHOLD: u64 = 10;
 
 #[derive(Parser)]
 struct Args {
     #[arg(short, long, action = clap::ArgAction::Count)]
     debug: u8,
+
+    /// Path to a TOML config file overriding the built-in defaults.
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Seconds of inactivity before the Touch Bar dims.
+    #[arg(long)]
+    dim_timeout: Option<u64>,
+
+    /// Seconds of inactivity before the Touch Bar turns off.
+    #[arg(long)]
+    off_timeout: Option<u64>,
+
+    /// Seconds to suspend automatic dimming after an external brightness change is observed.
+    #[arg(long)]
+    override_grace: Option<u64>,
+
+    /// Dim the Touch Bar in dark environments, as read from the ambient light sensor.
+    #[arg(long)]
+    ambient: Option<bool>,
+
+    /// Lux threshold below which the ambient light sensor caps brightness at `Dim`.
+    #[arg(long)]
+    ambient_threshold: Option<u64>,
+}
+
+/// Values loaded from the optional TOML config file, e.g. `/etc/t2kbfnd.toml`.
+#[derive(Debug, Default, Deserialize)]
+struct FileConfig {
+    dim_timeout: Option<u64>,
+    off_timeout: Option<u64>,
+    override_grace: Option<u64>,
+    ambient: Option<bool>,
+    ambient_threshold: Option<u64>,
+}
+
+/// Resolved config: CLI flags override the config file, which overrides the defaults.
+#[derive(Debug, Copy, Clone)]
+struct Config {
+    dim_timeout: u64,
+    off_timeout: u64,
+    override_grace: Duration,
+    ambient: bool,
+    ambient_threshold: u64,
+}
+
+impl Config {
+    fn resolve(args: &Args) -> Result<Self> {
+        let file_config = match &args.config {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read config file {}", path.display()))?;
+                toml::from_str(&contents)
+                    .with_context(|| format!("Failed to parse config file {}", path.display()))?
+            }
+            None => FileConfig::default(),
+        };
+
+        Ok(Self {
+            dim_timeout: args
+                .dim_timeout
+                .or(file_config.dim_timeout)
+                .unwrap_or(DEFAULT_DIM_TIMEOUT),
+            off_timeout: args
+                .off_timeout
+                .or(file_config.off_timeout)
+                .unwrap_or(DEFAULT_OFF_TIMEOUT),
+            override_grace: Duration::from_secs(
+                args.override_grace
+                    .or(file_config.override_grace)
+                    .unwrap_or(DEFAULT_OVERRIDE_GRACE),
+            ),
+            ambient: args.ambient.or(file_config.ambient).unwrap_or(false),
+            ambient_threshold: args
+                .ambient_threshold
+                .or(file_config.ambient_threshold)
+                .unwrap_or(DEFAULT_AMBIENT_THRESHOLD),
+        })
+    }
 }
 
 const TOUCHBAR_BACKLIGHT_PATH: &str = "/sys/class/backlight/appletb_backlight/brightness";
+const KEYBOARD_BACKLIGHT_DIR: &str = "/sys/class/leds/kbd_backlight";
 const KEYBOARD_EVENT_PATH: &str = "/dev/input/by-id/*Apple_Internal_Keyboard*event-kbd";
 const TRACKPAD_EVENT_PATH: &str = "/dev/input/by-id/*Apple_Internal_Keyboard*event-mouse";
+const AMBIENT_LIGHT_PATH: &str = "/sys/bus/iio/devices/iio:device*/in_illuminance_input";
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
-enum TbBacklightMode {
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+enum BacklightMode {
     Off = 0,
     Dim = 1,
     Max = 2,
 }
 
-struct TbBacklight {
-    pub state: TbBacklightMode,
+fn read_backlight_mode() -> Result<BacklightMode> {
+    let mut read_fd = File::open(TOUCHBAR_BACKLIGHT_PATH)?;
+    let mut buf = String::new();
+    read_fd.read_to_string(&mut buf)?;
+    match buf.trim() {
+        "0" => Ok(BacklightMode::Off),
+        "1" => Ok(BacklightMode::Dim),
+        "2" => Ok(BacklightMode::Max),
+        _ => Err(anyhow!("Touchbar backlight state unknown")),
+    }
+}
+
+/// A single backlit device the daemon can dim on its own inactivity/ambient timers.
+trait Backlight: Send {
+    fn current(&self) -> BacklightMode;
+    fn set(&mut self, mode: BacklightMode) -> Result<()>;
+}
+
+struct TouchBarBacklight {
+    state: Arc<std::sync::RwLock<BacklightMode>>,
     fd: File,
 }
 
-impl TbBacklight {
+impl TouchBarBacklight {
+    fn new(state: Arc<std::sync::RwLock<BacklightMode>>) -> Result<Self> {
+        let fd = OpenOptions::new()
+            .write(true)
+            .read(false)
+            .open(TOUCHBAR_BACKLIGHT_PATH)?;
+        Ok(Self { state, fd })
+    }
+}
+
+impl Backlight for TouchBarBacklight {
+    fn current(&self) -> BacklightMode {
+        *self.state.read().unwrap()
+    }
+
+    fn set(&mut self, mode: BacklightMode) -> Result<()> {
+        let mut state = self.state.write().unwrap();
+        if *state != mode {
+            trace!("Setting touch bar brightness to {}", mode as u32);
+            self.fd.write_all(format!("{}", mode as u32).as_bytes())?;
+            *state = mode;
+        }
+        Ok(())
+    }
+}
+
+/// The internal keyboard backlight, exposed through the standard `leds` sysfs class rather than
+/// `backlight`. Unlike the Touch Bar it takes a raw 0..=max_brightness value, so `Dim` maps to the
+/// midpoint instead of a fixed `1`.
+struct KeyboardBacklight {
+    state: BacklightMode,
+    fd: File,
+    max_brightness: u32,
+}
+
+impl KeyboardBacklight {
     fn new() -> Result<Self> {
-        let mut read_fd = File::open(TOUCHBAR_BACKLIGHT_PATH)?;
+        let dir = Path::new(KEYBOARD_BACKLIGHT_DIR);
+        let max_brightness: u32 = std::fs::read_to_string(dir.join("max_brightness"))?
+            .trim()
+            .parse()
+            .context("Invalid keyboard backlight max_brightness")?;
+
+        let mut read_fd = File::open(dir.join("brightness"))?;
         let mut buf = String::new();
         read_fd.read_to_string(&mut buf)?;
+        let raw: u32 = buf
+            .trim()
+            .parse()
+            .context("Invalid keyboard backlight brightness")?;
+        let state = if raw == 0 {
+            BacklightMode::Off
+        } else if raw >= max_brightness {
+            BacklightMode::Max
+        } else {
+            BacklightMode::Dim
+        };
 
         let fd = OpenOptions::new()
             .write(true)
             .read(false)
-            .open(TOUCHBAR_BACKLIGHT_PATH)?;
-        let state = match buf.trim() {
-            "0" => TbBacklightMode::Off,
-            "1" => TbBacklightMode::Dim,
-            "2" => TbBacklightMode::Max,
-            _ => return Err(anyhow!("Touchbar backlight state unknown")),
-        };
-        Ok(Self { state, fd })
+            .open(dir.join("brightness"))?;
+        Ok(Self {
+            state,
+            fd,
+            max_brightness,
+        })
+    }
+
+    fn raw_value(&self, mode: BacklightMode) -> u32 {
+        match mode {
+            BacklightMode::Off => 0,
+            BacklightMode::Dim => (self.max_brightness / 2).max(1),
+            BacklightMode::Max => self.max_brightness,
+        }
+    }
+}
+
+impl Backlight for KeyboardBacklight {
+    fn current(&self) -> BacklightMode {
+        self.state
     }
 
-    fn set_brightness(&mut self, mode: TbBacklightMode) -> Result<()> {
+    fn set(&mut self, mode: BacklightMode) -> Result<()> {
         if self.state != mode {
-            trace!("Setting brightness to {}", mode as u32);
-            self.fd.write_all(format!("{}", mode as u32).as_bytes())?;
+            let raw = self.raw_value(mode);
+            trace!("Setting keyboard backlight to {raw}");
+            self.fd.write_all(format!("{raw}").as_bytes())?;
             self.state = mode;
         }
         Ok(())
     }
 }
 
+/// Watches [`TOUCHBAR_BACKLIGHT_PATH`] for writes from outside this process (another tool, or the
+/// user poking sysfs directly) and, when a non-[`BacklightMode::Max`] value shows up that we
+/// didn't just write ourselves, suspends automatic dimming for `grace` so we don't fight the
+/// external controller.
+fn spawn_backlight_watcher(
+    state: Arc<std::sync::RwLock<BacklightMode>>,
+    suspended_until: Arc<RwLock<Option<Instant>>>,
+    grace: Duration,
+) -> Result<tokio::task::JoinHandle<()>> {
+    let mut inotify = Inotify::init().context("Failed to initialize inotify")?;
+    inotify
+        .watches()
+        .add(TOUCHBAR_BACKLIGHT_PATH, WatchMask::MODIFY)
+        .context("Failed to watch touchbar backlight sysfs node")?;
+
+    Ok(tokio::task::spawn(async move {
+        let buffer = [0; 1024];
+        let mut events = match inotify.into_event_stream(buffer) {
+            Ok(events) => events,
+            Err(err) => {
+                warn!("Failed to start backlight watcher: {err}");
+                return;
+            }
+        };
+        while let Some(event) = events.next().await {
+            if event.is_err() {
+                continue;
+            }
+            let observed = match read_backlight_mode() {
+                Ok(mode) => mode,
+                Err(err) => {
+                    warn!("Failed to read backlight after external write: {err}");
+                    continue;
+                }
+            };
+            let mut current = state.write().unwrap();
+            let should_suspend = observed != *current && observed != BacklightMode::Max;
+            *current = observed;
+            drop(current);
+            if should_suspend {
+                trace!("Detected external backlight override, suspending auto-dim");
+                *suspended_until.write().await = Some(Instant::now() + grace);
+            }
+        }
+    }))
+}
+
+/// Reads illuminance from the ambient light sensor's IIO sysfs node.
+struct AmbientSensor {
+    path: PathBuf,
+}
+
+impl AmbientSensor {
+    fn new() -> Result<Self> {
+        let path = glob(AMBIENT_LIGHT_PATH)?
+            .next()
+            .context("Ambient light sensor not found")??;
+        Ok(Self { path })
+    }
+
+    fn read_lux(&self) -> Result<u64> {
+        let mut buf = String::new();
+        File::open(&self.path)?.read_to_string(&mut buf)?;
+        buf.trim()
+            .parse()
+            .with_context(|| format!("Invalid lux value in {}", self.path.display()))
+    }
+}
+
+/// Polls the ambient light sensor and maintains the brightness ceiling it imposes: `Max` in
+/// bright rooms, `Dim` once illuminance drops below `threshold`. The backlight task combines this
+/// ceiling with the inactivity timers, taking whichever mode is dimmer.
+fn spawn_ambient_task(
+    sensor: AmbientSensor,
+    threshold: u64,
+    ceiling: Arc<RwLock<BacklightMode>>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::task::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            match sensor.read_lux() {
+                Ok(lux) => {
+                    let mode = if lux >= threshold {
+                        BacklightMode::Max
+                    } else {
+                        BacklightMode::Dim
+                    };
+                    *ceiling.write().await = mode;
+                }
+                Err(err) => warn!("Failed to read ambient light sensor: {err}"),
+            }
+        }
+    })
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
@@ -79,36 +352,108 @@ async fn main() -> Result<()> {
         simplelog::ColorChoice::Auto,
     );
 
+    let config = Config::resolve(&args)?;
+
     let time_lock = Arc::new(RwLock::new(Instant::now()));
 
-    let mut touchbar_backlight = TbBacklight::new()?;
+    let backlight_state = Arc::new(std::sync::RwLock::new(read_backlight_mode()?));
+    let suspended_until = Arc::new(RwLock::new(None));
+    let mut backlight_watcher_task = spawn_backlight_watcher(
+        backlight_state.clone(),
+        suspended_until.clone(),
+        config.override_grace,
+    )?;
+
+    let ambient_ceiling = Arc::new(RwLock::new(BacklightMode::Max));
+    let mut ambient_task = if config.ambient {
+        Some(spawn_ambient_task(
+            AmbientSensor::new()?,
+            config.ambient_threshold,
+            ambient_ceiling.clone(),
+        ))
+    } else {
+        None
+    };
+
+    let mut backlights: Vec<(Box<dyn Backlight>, u32)> =
+        vec![(Box::new(TouchBarBacklight::new(backlight_state)?), 0)];
+    match KeyboardBacklight::new() {
+        Ok(keyboard_backlight) => backlights.push((Box::new(keyboard_backlight), 0)),
+        Err(err) => warn!("Keyboard backlight unavailable, skipping: {err}"),
+    }
+    let backlights = Arc::new(Mutex::new(backlights));
+
     let backlight_time_lock = time_lock.clone();
-    let _backlight_task = tokio::task::spawn(async move {
+    let backlight_task_backlights = backlights.clone();
+    let mut backlight_task = tokio::task::spawn(async move {
         let mut interval = time::interval(Duration::from_millis(100));
-        let mut failure_counter = 0;
         loop {
             interval.tick().await;
-            let inactive_time = backlight_time_lock.read().await.elapsed().as_secs();
-            touchbar_backlight
-                .set_brightness(if inactive_time >= 60 {
-                    TbBacklightMode::Off
-                } else if inactive_time >= 30 {
-                    TbBacklightMode::Dim
-                } else {
-                    TbBacklightMode::Max
-                })
-                .unwrap_or_else(|_| failure_counter += 1);
-            if failure_counter >= 3 {
-                return;
+
+            if let Some(until) = *suspended_until.read().await {
+                if Instant::now() < until {
+                    continue;
+                }
             }
+
+            let inactive_time = backlight_time_lock.read().await.elapsed().as_secs();
+            let inactivity_mode = if inactive_time >= config.off_timeout {
+                BacklightMode::Off
+            } else if inactive_time >= config.dim_timeout {
+                BacklightMode::Dim
+            } else {
+                BacklightMode::Max
+            };
+            let mode = inactivity_mode.min(*ambient_ceiling.read().await);
+            backlight_task_backlights
+                .lock()
+                .await
+                .retain_mut(|(backlight, failures)| match backlight.set(mode) {
+                    Ok(()) => {
+                        *failures = 0;
+                        true
+                    }
+                    Err(err) => {
+                        *failures += 1;
+                        if *failures >= 3 {
+                            warn!("Disabling backlight after repeated failures: {err}");
+                        }
+                        *failures < 3
+                    }
+                });
         }
     });
 
     let keyboard_events = get_event_fd(KEYBOARD_EVENT_PATH)?.into_event_stream()?;
-    let _keyboard_event_task = create_event_moniter(keyboard_events, time_lock.clone());
+    let mut keyboard_event_task = create_event_moniter(keyboard_events, time_lock.clone());
 
     let trackpad_events = get_event_fd(TRACKPAD_EVENT_PATH)?.into_event_stream()?;
-    let _trackpad_event_task = create_event_moniter(trackpad_events, time_lock.clone());
+    let mut trackpad_event_task = create_event_moniter(trackpad_events, time_lock.clone());
+
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT, shutting down"),
+        _ = sigterm.recv() => info!("Received SIGTERM, shutting down"),
+        _ = &mut backlight_task => warn!("Backlight task exited unexpectedly"),
+        _ = &mut backlight_watcher_task => warn!("Backlight watcher task exited unexpectedly"),
+        _ = OptionFuture::from(ambient_task.as_mut()), if ambient_task.is_some() => warn!("Ambient sensor task exited unexpectedly"),
+        _ = &mut keyboard_event_task => warn!("Keyboard event task exited unexpectedly"),
+        _ = &mut trackpad_event_task => warn!("Trackpad event task exited unexpectedly"),
+    }
+
+    backlight_task.abort();
+    backlight_watcher_task.abort();
+    if let Some(task) = &ambient_task {
+        task.abort();
+    }
+    keyboard_event_task.abort();
+    trackpad_event_task.abort();
+
+    for (backlight, _) in backlights.lock().await.iter_mut() {
+        if let Err(err) = backlight.set(BacklightMode::Max) {
+            warn!("Failed to restore backlight to Max on shutdown: {err}");
+        }
+    }
 
     Ok(())
 }